@@ -3,19 +3,74 @@ use crate::parse_format::get_args_and_keywords;
 use crate::{FILENAME, SETTINGS};
 use anyhow::bail;
 use anyhow::Result;
-use rustpython_parser::ast::{Expr, ExprKind};
+use rustpython_parser::ast::{Boolop, Cmpop, Expr, ExprKind, Unaryop};
+
+/// Render a `BoolOp` operator (`and`/`or`) the way it appears in source.
+fn boolop_to_string(op: &Boolop) -> &'static str {
+    match op {
+        Boolop::And => "and",
+        Boolop::Or => "or",
+    }
+}
+
+/// Render a `UnaryOp` operator (`not`/`-`/`+`/`~`) the way it appears in source.
+fn unaryop_to_string(op: &Unaryop) -> &'static str {
+    match op {
+        Unaryop::Invert => "~",
+        Unaryop::Not => "not",
+        Unaryop::UAdd => "+",
+        Unaryop::USub => "-",
+    }
+}
+
+/// Render a `Compare` operator the way it appears in source. `Operator`
+/// (used for `BinOp`) and `Cmpop` are distinct enums in the AST, so this
+/// can't reuse `operator_to_string`.
+fn cmpop_to_string(op: &Cmpop) -> &'static str {
+    match op {
+        Cmpop::Eq => "==",
+        Cmpop::NotEq => "!=",
+        Cmpop::Lt => "<",
+        Cmpop::LtE => "<=",
+        Cmpop::Gt => ">",
+        Cmpop::GtE => ">=",
+        Cmpop::Is => "is",
+        Cmpop::IsNot => "is not",
+        Cmpop::In => "in",
+        Cmpop::NotIn => "not in",
+    }
+}
 
 /// Parse `FormattedValue` AST ({something})
 pub fn parse_formatted_value(value: &Expr, postfix: String, in_call: bool) -> Result<String> {
-    let string = match &value.node {
+    let quote_char = SETTINGS.get().unwrap().quotes.clone().char();
+    let mut out = String::new();
+    write_formatted_value(value, &postfix, in_call, quote_char, &mut out)?;
+    Ok(out)
+}
+
+/// Recursive worker behind [`parse_formatted_value`]. Writes directly into
+/// `out` instead of returning an owned `String` at every level, and takes
+/// `postfix` by reference and `quote_char` pre-resolved, so nested calls,
+/// operators and comprehensions no longer build and copy an intermediate
+/// `String` per level. An attribute chain still builds a new joined
+/// `postfix` at each `Attribute` level.
+fn write_formatted_value(
+    value: &Expr,
+    postfix: &str,
+    in_call: bool,
+    quote_char: char,
+    out: &mut String,
+) -> Result<()> {
+    match &value.node {
         // When we see a Name node we're typically handling a variable.
         // In this case, we want variables to be referenced with %s, and
         // for the variable definition to be placed after our string.
         ExprKind::Name { id, .. } => {
-            if postfix.is_empty() {
-                id.to_string()
-            } else {
-                format!("{id}.{postfix}")
+            out.push_str(id);
+            if !postfix.is_empty() {
+                out.push('.');
+                out.push_str(postfix);
             }
         }
         // An attribute node is typically an intermediate node
@@ -23,24 +78,21 @@ pub fn parse_formatted_value(value: &Expr, postfix: String, in_call: bool) -> Re
         // to reconstruct the entire chain of attributes + names in the end.
         ExprKind::Attribute { value, attr, .. } => {
             if postfix.is_empty() {
-                parse_formatted_value(value, attr.to_string(), false)?
+                write_formatted_value(value, attr, false, quote_char, out)?;
             } else {
-                parse_formatted_value(value, format!("{attr}.{postfix}"), false)?
+                let joined = format!("{attr}.{postfix}");
+                write_formatted_value(value, &joined, false, quote_char, out)?;
             }
         }
         // A constant is a value like 1 or None.
         // We want these values to be moved out of the string.
         ExprKind::Constant { value, .. } => {
             if in_call {
-                let quotes = SETTINGS.get().unwrap().quotes.clone();
-                format!(
-                    "{}{}{}",
-                    quotes.char(),
-                    constant_to_string(value.clone()),
-                    quotes.char()
-                )
+                out.push(quote_char);
+                out.push_str(&constant_to_string(value.clone()));
+                out.push(quote_char);
             } else {
-                constant_to_string(value.clone())
+                out.push_str(&constant_to_string(value.clone()));
             }
         }
         // Calls are function calls. So for example we might see f"{len(foo)}" in an f-string.
@@ -54,51 +106,40 @@ pub fn parse_formatted_value(value: &Expr, postfix: String, in_call: bool) -> Re
             let (f_args, f_named_args) = get_args_and_keywords(call_args, keywords)?;
             match &func.node {
                 ExprKind::Name { id, .. } => {
+                    out.push_str(id);
+                    out.push('(');
+                    out.push_str(&f_args.join(", "));
                     // Create a string with `x=y` for all named arguments and prefix it
                     // with a comma unless the string ends up being empty.
-                    let mut comma_delimited_named_arguments = f_named_args
-                        .into_iter()
-                        .map(|arg| format!("{}={}", arg.key, constant_to_string(arg.value)))
-                        .collect::<Vec<String>>()
-                        .join(", ");
-                    if !comma_delimited_named_arguments.is_empty() {
-                        comma_delimited_named_arguments =
-                            ", ".to_string() + &comma_delimited_named_arguments;
+                    if !f_named_args.is_empty() {
+                        out.push_str(", ");
+                        out.push_str(
+                            &f_named_args
+                                .into_iter()
+                                .map(|arg| format!("{}={}", arg.key, constant_to_string(arg.value)))
+                                .collect::<Vec<String>>()
+                                .join(", "),
+                        );
                     }
-
-                    // Finally, push the reconstructed function call to the outside of the string
-                    // and just add a %s in the string.
-                    format!(
-                        "{}({}{})",
-                        id,
-                        f_args.join(", "),
-                        comma_delimited_named_arguments
-                    )
+                    out.push(')');
                 }
                 ExprKind::Attribute { value, attr, .. } => {
-                    let call = {
-                        let mut s = "(".to_string();
-                        for arg in f_args {
-                            // TODO: DO the whole first arg, not first arg-dance
-                            s.push_str(&format!("{},", arg))
-                        }
-                        for kwarg in f_named_args {
-                            s.push_str(&format!(
-                                "{}={},",
-                                kwarg.key,
-                                constant_to_string(kwarg.value)
-                            ))
-                        }
-                        s.push(')');
-                        s
-                    };
-
-                    format!(
-                        "{}.{}{}",
-                        parse_formatted_value(value, postfix, true)?,
-                        attr,
-                        call
-                    )
+                    write_formatted_value(value, postfix, true, quote_char, out)?;
+                    out.push('.');
+                    out.push_str(attr);
+                    out.push('(');
+                    for arg in f_args {
+                        // TODO: DO the whole first arg, not first arg-dance
+                        out.push_str(&arg);
+                        out.push(',');
+                    }
+                    for kwarg in f_named_args {
+                        out.push_str(&kwarg.key);
+                        out.push('=');
+                        out.push_str(&constant_to_string(kwarg.value));
+                        out.push(',');
+                    }
+                    out.push(')');
                 }
                 _ => {
                     let filename = FILENAME.with(std::clone::Clone::clone);
@@ -109,54 +150,192 @@ pub fn parse_formatted_value(value: &Expr, postfix: String, in_call: bool) -> Re
             }
         }
         ExprKind::BinOp { left, op, right } => {
-            format!(
-                "{} {} {}",
-                parse_formatted_value(left, postfix.clone(), false)?,
-                operator_to_string(op),
-                parse_formatted_value(right, postfix, false)?
-            )
+            write_formatted_value(left, postfix, false, quote_char, out)?;
+            out.push(' ');
+            out.push_str(&operator_to_string(op));
+            out.push(' ');
+            write_formatted_value(right, postfix, false, quote_char, out)?;
+        }
+        // A plain subscript (`a[b]`) is almost always a dict-style lookup in
+        // practice, so we quote the key. A `Slice` subscript (`a[b:c]`) is
+        // not a key though, so we reconstruct it unquoted instead.
+        ExprKind::Subscript { value, slice, .. }
+            if matches!(slice.node, ExprKind::Slice { .. }) =>
+        {
+            write_formatted_value(value, postfix, false, quote_char, out)?;
+            out.push('[');
+            write_formatted_value(slice, postfix, false, quote_char, out)?;
+            out.push(']');
         }
         ExprKind::Subscript { value, slice, .. } => {
-            let quotes = SETTINGS.get().unwrap().quotes.clone();
-            format!(
-                "{}[{}{}{}]",
-                parse_formatted_value(value, postfix.clone(), false)?,
-                quotes.char(),
-                parse_formatted_value(slice, postfix, false)?,
-                quotes.char()
-            )
+            write_formatted_value(value, postfix, false, quote_char, out)?;
+            out.push('[');
+            out.push(quote_char);
+            write_formatted_value(slice, postfix, false, quote_char, out)?;
+            out.push(quote_char);
+            out.push(']');
+        }
+        ExprKind::Slice { lower, upper, step } => {
+            if let Some(lower) = lower {
+                write_formatted_value(lower, postfix, false, quote_char, out)?;
+            }
+            out.push(':');
+            if let Some(upper) = upper {
+                write_formatted_value(upper, postfix, false, quote_char, out)?;
+            }
+            if let Some(step) = step {
+                out.push(':');
+                write_formatted_value(step, postfix, false, quote_char, out)?;
+            }
+        }
+        ExprKind::IfExp { test, body, orelse } => {
+            write_formatted_value(body, postfix, false, quote_char, out)?;
+            out.push_str(" if ");
+            write_formatted_value(test, postfix, false, quote_char, out)?;
+            out.push_str(" else ");
+            write_formatted_value(orelse, postfix, false, quote_char, out)?;
+        }
+        ExprKind::Compare {
+            left,
+            ops,
+            comparators,
+        } => {
+            write_formatted_value(left, postfix, false, quote_char, out)?;
+            for (op, comparator) in ops.iter().zip(comparators) {
+                out.push(' ');
+                out.push_str(cmpop_to_string(op));
+                out.push(' ');
+                write_formatted_value(comparator, postfix, false, quote_char, out)?;
+            }
+        }
+        ExprKind::BoolOp { op, values } => {
+            let sep = boolop_to_string(op);
+            for (i, v) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                    out.push_str(sep);
+                    out.push(' ');
+                }
+                write_formatted_value(v, postfix, false, quote_char, out)?;
+            }
+        }
+        ExprKind::UnaryOp { op, operand } => {
+            let op_str = unaryop_to_string(op);
+            out.push_str(op_str);
+            if op_str == "not" {
+                out.push(' ');
+            }
+            write_formatted_value(operand, postfix, false, quote_char, out)?;
+        }
+        ExprKind::Tuple { elts, .. } => {
+            out.push('(');
+            for (i, e) in elts.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_formatted_value(e, postfix, true, quote_char, out)?;
+            }
+            // a single-element tuple needs a trailing comma to stay a tuple
+            if elts.len() == 1 {
+                out.push(',');
+            }
+            out.push(')');
+        }
+        ExprKind::List { elts, .. } => {
+            out.push('[');
+            for (i, e) in elts.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_formatted_value(e, postfix, true, quote_char, out)?;
+            }
+            out.push(']');
+        }
+        ExprKind::Set { elts } => {
+            out.push('{');
+            for (i, e) in elts.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_formatted_value(e, postfix, true, quote_char, out)?;
+            }
+            out.push('}');
+        }
+        ExprKind::Dict { keys, values } => {
+            out.push('{');
+            for (i, (key, value)) in keys.iter().zip(values).enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                match key {
+                    Some(key) => {
+                        write_formatted_value(key, postfix, true, quote_char, out)?;
+                        out.push_str(": ");
+                        write_formatted_value(value, postfix, true, quote_char, out)?;
+                    }
+                    // `None` keys are `**other` dict-unpacking entries
+                    None => {
+                        out.push_str("**");
+                        write_formatted_value(value, postfix, true, quote_char, out)?;
+                    }
+                }
+            }
+            out.push('}');
+        }
+        ExprKind::Starred { value, .. } => {
+            out.push('*');
+            write_formatted_value(value, postfix, in_call, quote_char, out)?;
+        }
+        ExprKind::GeneratorExp { elt, generators } => {
+            out.push('(');
+            write_formatted_value(elt, postfix, true, quote_char, out)?;
+            for generator in generators {
+                out.push_str(" for ");
+                write_formatted_value(&generator.target, postfix, true, quote_char, out)?;
+                out.push_str(" in ");
+                write_formatted_value(&generator.iter, postfix, true, quote_char, out)?;
+                for if_clause in &generator.ifs {
+                    out.push_str(" if ");
+                    write_formatted_value(if_clause, postfix, true, quote_char, out)?;
+                }
+            }
+            out.push(')');
         }
         ExprKind::ListComp { elt, generators } => {
-            let mut s = format!("[{}", parse_formatted_value(elt, postfix.clone(), true)?,);
+            out.push('[');
+            write_formatted_value(elt, postfix, true, quote_char, out)?;
             for generator in generators {
-                s.push_str(&format!(
-                    " for {} in {}",
-                    parse_formatted_value(&generator.target, postfix.clone(), true)?,
-                    parse_formatted_value(&generator.iter, postfix.clone(), true)?
-                ))
+                out.push_str(" for ");
+                write_formatted_value(&generator.target, postfix, true, quote_char, out)?;
+                out.push_str(" in ");
+                write_formatted_value(&generator.iter, postfix, true, quote_char, out)?;
+                for if_clause in &generator.ifs {
+                    out.push_str(" if ");
+                    write_formatted_value(if_clause, postfix, true, quote_char, out)?;
+                }
             }
-            s.push(']');
-            s
+            out.push(']');
         }
         ExprKind::DictComp {
             key,
             value,
             generators,
         } => {
-            let mut s = format!(
-                "{{{}: {}",
-                parse_formatted_value(key, postfix.clone(), true)?,
-                parse_formatted_value(value, postfix.clone(), true)?,
-            );
+            out.push('{');
+            write_formatted_value(key, postfix, true, quote_char, out)?;
+            out.push_str(": ");
+            write_formatted_value(value, postfix, true, quote_char, out)?;
             for generator in generators {
-                s.push_str(&format!(
-                    " for {} in {}",
-                    parse_formatted_value(&generator.target, postfix.clone(), true)?,
-                    parse_formatted_value(&generator.iter, postfix.clone(), true)?
-                ))
+                out.push_str(" for ");
+                write_formatted_value(&generator.target, postfix, true, quote_char, out)?;
+                out.push_str(" in ");
+                write_formatted_value(&generator.iter, postfix, true, quote_char, out)?;
+                for if_clause in &generator.ifs {
+                    out.push_str(" if ");
+                    write_formatted_value(if_clause, postfix, true, quote_char, out)?;
+                }
             }
-            s.push('}');
-            s
+            out.push('}');
         }
         _ => {
             let filename = FILENAME.with(std::clone::Clone::clone);
@@ -164,24 +343,193 @@ pub fn parse_formatted_value(value: &Expr, postfix: String, in_call: bool) -> Re
             eprintln!("{error_message}");
             bail!("");
         }
-    };
-    Ok(string)
+    }
+    Ok(())
+}
+
+/// Translate an f-string conversion flag (`!s`/`!r`/`!a`) and format spec
+/// (`:...`) into the closest printf-style conversion, e.g. `s`, `r`,
+/// `.2f`, `05d`, `8.3g`. Returns `Err` when the spec uses a feature
+/// (fill, alignment, sign, grouping) that `%`-formatting has no
+/// equivalent for, so the caller can leave the containing f-string alone.
+fn printf_conversion_spec(conversion: i32, format_spec: Option<&Expr>) -> Result<String> {
+    // ascii (!a) has no `%`-formatting equivalent, so fall back to %s
+    let fallback_type = if conversion == 114 { 'r' } else { 's' };
+    match format_spec {
+        Some(format_spec) => parse_format_spec(&format_spec_text(format_spec)?, fallback_type),
+        None => Ok(fallback_type.to_string()),
+    }
+}
+
+/// Concatenate the constant segments of a `format_spec`'s `JoinedStr`,
+/// e.g. the `.2f` in `f"{pi:.2f}"`. Bails on a dynamic spec (one
+/// containing a nested `{}`), since `%`-formatting can't express that.
+fn format_spec_text(format_spec: &Expr) -> Result<String> {
+    match &format_spec.node {
+        ExprKind::JoinedStr { values } => {
+            let mut text = String::new();
+            for value in values {
+                match &value.node {
+                    ExprKind::Constant { value, .. } => {
+                        text.push_str(&constant_to_string(value.clone()));
+                    }
+                    _ => bail!(""),
+                }
+            }
+            Ok(text)
+        }
+        _ => bail!(""),
+    }
+}
+
+/// Parse the subset of Python's format-spec mini-language that `%`-style
+/// formatting can express: an optional `0` flag, a width, a `.precision`,
+/// and a trailing type char among `d/i/f/e/g/x/X/o/s`. Fill/align
+/// (`<`/`>`/`^`/`=`), sign (`+`) and grouping (`,`/`_`) bail, since
+/// printf has no equivalent for them. When the spec has no trailing type
+/// char, `fallback_type` is used instead (e.g. `r` for `f"{x!r:10}"`).
+fn parse_format_spec(spec: &str, fallback_type: char) -> Result<String> {
+    let mut chars = spec.chars().peekable();
+    let mut out = String::new();
+
+    if chars.peek() == Some(&'0') {
+        out.push('0');
+        chars.next();
+    }
+    while matches!(chars.peek(), Some(c) if c.is_ascii_digit()) {
+        out.push(chars.next().unwrap());
+    }
+    if chars.peek() == Some(&'.') {
+        out.push('.');
+        chars.next();
+        while matches!(chars.peek(), Some(c) if c.is_ascii_digit()) {
+            out.push(chars.next().unwrap());
+        }
+    }
+    match chars.next() {
+        Some(c) if "diefgxXos".contains(c) => out.push(c),
+        Some(_) => bail!(""),
+        None => out.push(fallback_type),
+    }
+    if chars.next().is_some() {
+        bail!("");
+    }
+    Ok(out)
+}
+
+/// Double every literal `%` in a constant segment of the reconstructed
+/// format string, since the string is destined to become the left
+/// operand of a `%`-format call and an unescaped `%` would either break
+/// or be swallowed as a (bogus) conversion at runtime.
+fn escape_percent(text: &str) -> String {
+    text.replace('%', "%%")
+}
+
+/// Whether `value` is a simple identifier chain (a bare name, or an
+/// attribute access bottoming out at one) we can derive a readable dict
+/// key from by reusing its raw reconstructed text. Anything else in the
+/// chain (a `Subscript`, `Call`, etc.) could embed characters like `)`
+/// that break `%(key)s` parsing, so those fall through to a synthesized
+/// `argN` key instead.
+fn is_simple_identifier(value: &Expr) -> bool {
+    match &value.node {
+        ExprKind::Name { .. } => true,
+        ExprKind::Attribute { value, .. } => is_simple_identifier(value),
+        _ => false,
+    }
+}
+
+/// The arguments accumulated for a rewritten logging call. Mirrors the two
+/// shapes `fix_fstring` can emit: a flat positional list
+/// (`logger.info("%s", x)`) or a name -> expression mapping
+/// (`logger.info("%(x)s", {"x": x})`), selected by `Settings.mapping_style`.
+pub enum FormatArgs {
+    Positional(Vec<String>),
+    Mapping(Vec<(String, String)>),
+}
+
+impl FormatArgs {
+    fn new() -> Self {
+        if SETTINGS.get().unwrap().mapping_style {
+            FormatArgs::Mapping(vec![])
+        } else {
+            FormatArgs::Positional(vec![])
+        }
+    }
+
+    /// Record `expr` (the reconstructed source of `value`) as an argument
+    /// for the placeholder currently being emitted, returning the key to
+    /// use in a `%(key)s` placeholder, or an empty string for positional
+    /// mode. Repeated references to the same expression reuse the same
+    /// key, so one dict entry can serve multiple placeholders.
+    fn push(&mut self, value: &Expr, expr: String) -> String {
+        match self {
+            FormatArgs::Positional(args) => {
+                args.push(expr);
+                String::new()
+            }
+            FormatArgs::Mapping(args) => {
+                if let Some((key, _)) = args.iter().find(|(_, existing)| existing == &expr) {
+                    return key.clone();
+                }
+                let candidate = if is_simple_identifier(value) {
+                    expr.replace('.', "_")
+                } else {
+                    format!("arg{}", args.len())
+                };
+                let key = Self::unique_key(args, candidate);
+                args.push((key.clone(), expr));
+                key
+            }
+        }
+    }
+
+    /// Disambiguate `candidate` against keys already in use, appending a
+    /// numeric suffix until it's unique. Without this, unrelated
+    /// expressions can derive or synthesize the same key (`obj.attr` and a
+    /// bare `obj_attr` variable both become `"obj_attr"`; a variable named
+    /// `arg0` collides with the first synthesized complex-expression key),
+    /// producing a Python dict literal with a duplicate key where the
+    /// second entry silently overwrites the first.
+    fn unique_key(args: &[(String, String)], candidate: String) -> String {
+        if !args.iter().any(|(key, _)| key == &candidate) {
+            return candidate;
+        }
+        let mut suffix = 1;
+        loop {
+            let deduped = format!("{candidate}{suffix}");
+            if !args.iter().any(|(key, _)| key == &deduped) {
+                return deduped;
+            }
+            suffix += 1;
+        }
+    }
 }
 
 /// Parse f-string AST
-fn parse_fstring(value: &Expr, string: &mut String, args: &mut Vec<String>) -> Result<()> {
+fn parse_fstring(value: &Expr, string: &mut String, args: &mut FormatArgs) -> Result<()> {
     match &value.node {
         // When we see a constant, we can just add it back to our new string directly
         ExprKind::Constant { value, .. } => {
-            string.push_str(&constant_to_string(value.clone()));
+            string.push_str(&escape_percent(&constant_to_string(value.clone())));
         }
         // A FormattedValue is the {} in an f-string.
         // Since a formatted value can contain constants, and we want to recursively
         // handle the structure, we'll handle the parsing of the formatted value in
         // a dedicated function.
-        ExprKind::FormattedValue { value, .. } => {
-            string.push_str("%s");
-            args.push(parse_formatted_value(value, String::new(), false)?);
+        ExprKind::FormattedValue {
+            value,
+            conversion,
+            format_spec,
+        } => {
+            let spec = printf_conversion_spec(*conversion, format_spec.as_deref())?;
+            let expr = parse_formatted_value(value, String::new(), false)?;
+            let key = args.push(value, expr);
+            if key.is_empty() {
+                string.push_str(&format!("%{spec}"));
+            } else {
+                string.push_str(&format!("%({key}){spec}"));
+            }
         }
         _ => {
             let filename = FILENAME.with(std::clone::Clone::clone);
@@ -193,9 +541,9 @@ fn parse_fstring(value: &Expr, string: &mut String, args: &mut Vec<String>) -> R
     Ok(())
 }
 
-pub fn fix_fstring(values: &[Expr]) -> Option<(String, Vec<String>)> {
+pub fn fix_fstring(values: &[Expr]) -> Option<(String, FormatArgs)> {
     let mut string = String::new();
-    let mut args = vec![];
+    let mut args = FormatArgs::new();
 
     for value in values {
         match parse_fstring(value, &mut string, &mut args) {
@@ -206,3 +554,115 @@ pub fn fix_fstring(values: &[Expr]) -> Option<(String, Vec<String>)> {
 
     Some((string, args))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustpython_parser::ast::Cmpop;
+
+    // These pin down the output of the small, settings-independent helpers
+    // that `fix_fstring` and `write_formatted_value` delegate to, so the
+    // buffer rewrite can't silently change what gets written for the same
+    // inputs.
+
+    #[test]
+    fn parse_format_spec_handles_the_printf_compatible_subset() {
+        assert_eq!(parse_format_spec(".2f", 's').unwrap(), ".2f");
+        assert_eq!(parse_format_spec("05d", 's').unwrap(), "05d");
+        assert_eq!(parse_format_spec("8.3g", 's').unwrap(), "8.3g");
+        assert_eq!(parse_format_spec("", 's').unwrap(), "s");
+        assert_eq!(parse_format_spec("10", 'r').unwrap(), "10r");
+    }
+
+    #[test]
+    fn parse_format_spec_bails_on_features_percent_formatting_cant_express() {
+        assert!(parse_format_spec("<10", 's').is_err());
+        assert!(parse_format_spec("+d", 's').is_err());
+        assert!(parse_format_spec(",.2f", 's').is_err());
+    }
+
+    #[test]
+    fn escape_percent_only_doubles_percent_signs() {
+        assert_eq!(escape_percent("50% done"), "50%% done");
+        assert_eq!(escape_percent("no percent here"), "no percent here");
+    }
+
+    #[test]
+    fn cmpop_to_string_matches_python_source() {
+        assert_eq!(cmpop_to_string(&Cmpop::Eq), "==");
+        assert_eq!(cmpop_to_string(&Cmpop::NotEq), "!=");
+        assert_eq!(cmpop_to_string(&Cmpop::Is), "is");
+        assert_eq!(cmpop_to_string(&Cmpop::NotIn), "not in");
+    }
+
+    #[test]
+    fn boolop_and_unaryop_to_string_match_python_source() {
+        assert_eq!(boolop_to_string(&Boolop::And), "and");
+        assert_eq!(boolop_to_string(&Boolop::Or), "or");
+        assert_eq!(unaryop_to_string(&Unaryop::Not), "not");
+        assert_eq!(unaryop_to_string(&Unaryop::USub), "-");
+    }
+
+    // The tests below drive `fix_fstring` end to end through real parsed
+    // f-strings, so the buffer rewrite in `write_formatted_value` is
+    // exercised the way it actually runs rather than just through its
+    // standalone helpers.
+
+    use crate::{Quotes, Settings};
+    use rustpython_parser::parser::parse_expression;
+
+    fn init_settings() {
+        SETTINGS.get_or_init(|| Settings {
+            quotes: Quotes::Single,
+            mapping_style: false,
+        });
+    }
+
+    fn joined_str_values(src: &str) -> Vec<Expr> {
+        match parse_expression(src, "<test>").unwrap().node {
+            ExprKind::JoinedStr { values } => values,
+            _ => panic!("expected `{src}` to parse as a JoinedStr"),
+        }
+    }
+
+    fn positional_args(args: FormatArgs) -> Vec<String> {
+        match args {
+            FormatArgs::Positional(args) => args,
+            FormatArgs::Mapping(_) => panic!("expected positional args"),
+        }
+    }
+
+    #[test]
+    fn fix_fstring_reconstructs_attribute_chains() {
+        init_settings();
+        let values = joined_str_values(r#"f"{a.b.c}""#);
+        let (template, args) = fix_fstring(&values).unwrap();
+        assert_eq!(template, "%s");
+        assert_eq!(positional_args(args), vec!["a.b.c".to_string()]);
+    }
+
+    #[test]
+    fn fix_fstring_reconstructs_list_comprehension_filters() {
+        init_settings();
+        let values = joined_str_values(r#"f"{[item.name for item in results if item.active]}""#);
+        let (template, args) = fix_fstring(&values).unwrap();
+        assert_eq!(template, "%s");
+        assert_eq!(
+            positional_args(args),
+            vec!["[item.name for item in results if item.active]".to_string()]
+        );
+    }
+
+    #[test]
+    fn fix_fstring_reconstructs_dict_comprehension_with_nested_call() {
+        init_settings();
+        let values =
+            joined_str_values(r#"f"{ {k: v for k, v in mapping.items() if k.selected} }""#);
+        let (template, args) = fix_fstring(&values).unwrap();
+        assert_eq!(template, "%s");
+        assert_eq!(
+            positional_args(args),
+            vec!["{k: v for (k, v) in mapping.items() if k.selected}".to_string()]
+        );
+    }
+}